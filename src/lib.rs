@@ -16,8 +16,12 @@
 //! ```
 extern crate libc;
 
+use std::cell::RefCell;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::mem;
 use std::path::Path;
+use std::time::{Duration, Instant};
 use std::ptr::copy;
 use std::slice;
 
@@ -103,13 +107,14 @@ extern {
 /// A database connection
 pub struct Rlite {
     rlite: *mut c_void,
+    trace: RefCell<Option<Box<dyn FnMut(&[&[u8]], Duration)>>>,
 }
 
 impl Rlite {
     /// Create a new database in memory
     pub fn memory() -> Self {
         let rlite = unsafe { rliteConnect(":memory:".as_ptr() as *const c_char, 0) };
-        Rlite { rlite: rlite }
+        Rlite { rlite: rlite, trace: RefCell::new(None) }
     }
 
     /// Opens or creates a database in `path`.
@@ -120,12 +125,30 @@ impl Rlite {
         };
         let rlite = unsafe { rliteConnect(::std::ffi::CString::new(f).unwrap().as_ptr() as *const c_char, 0) };
         if rlite != 0 as *mut _ {
-            Ok(Rlite { rlite: rlite })
+            Ok(Rlite { rlite: rlite, trace: RefCell::new(None) })
         } else {
             Err(())
         }
     }
 
+    /// Registers a callback invoked after every `write_command` with the raw
+    /// argument vector and the wall-clock time spent submitting the command.
+    ///
+    /// The reported duration covers the `rliteAppendCommandArgv` call, which is
+    /// where the engine actually executes the command; the later
+    /// `rliteGetReply` that pops the queued reply is not included. Because a
+    /// reply is produced regardless of outcome, the callback fires even when
+    /// the command ultimately yields an error reply, which makes it suitable
+    /// for slow-command logging and lightweight profiling. Pass `None` to
+    /// remove a previously installed callback.
+    ///
+    /// The callback must not issue commands on the same connection: it runs
+    /// while the trace slot is borrowed, so re-entering `write_command` would
+    /// panic on a double borrow.
+    pub fn trace(&mut self, cb: Option<Box<dyn FnMut(&[&[u8]], Duration)>>) {
+        *self.trace.borrow_mut() = cb;
+    }
+
     /// Executes the command. It returns either success or error, with no detail.
     /// If it succeeded, use `read_reply` to get the response (if any).
     pub fn write_command(&self, command: &[&[u8]]) -> Result<(), ()> {
@@ -135,12 +158,18 @@ impl Rlite {
             argv.push(c.as_ptr());
             argvlen.push(c.len() as size_t);
         }
-        unsafe {
-            if rliteAppendCommandArgv(self.rlite, command.len() as c_int, argv.as_ptr(), argvlen.as_ptr()) == 0 {
-                Ok(())
-            } else {
-                Err(())
-            }
+        let start = Instant::now();
+        let ret = unsafe {
+            rliteAppendCommandArgv(self.rlite, command.len() as c_int, argv.as_ptr(), argvlen.as_ptr())
+        };
+        let elapsed = start.elapsed();
+        if let Some(ref mut cb) = *self.trace.borrow_mut() {
+            cb(command, elapsed);
+        }
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(())
         }
     }
 
@@ -162,6 +191,401 @@ impl Rlite {
             }
         }
     }
+
+    /// Writes a command and immediately reads its reply, returning a `String`
+    /// error on either a write failure or an error reply.
+    fn query(&self, command: &[&[u8]]) -> Result<Reply, String> {
+        if self.write_command(command).is_err() {
+            return Err("failed to write command".to_owned());
+        }
+        self.read_reply()
+    }
+
+    /// Copies every key of this database into `dst` without closing either
+    /// handle, mirroring the online backup facility found in SQLite.
+    ///
+    /// The copy runs entirely on top of commands: the source is walked with
+    /// `SCAN`, each key is serialized with `DUMP` and its remaining expiry read
+    /// with `PTTL`, and the pair is written to `dst` with `RESTORE ... REPLACE`
+    /// so TTLs survive the trip. When `progress` is supplied it is invoked once
+    /// per scanned batch with an estimate derived from `DBSIZE`.
+    pub fn backup(&self, dst: &mut Rlite, progress: Option<fn(Progress)>) -> Result<(), String> {
+        let total = match try!(self.query(&[b"DBSIZE"])) {
+            Reply::Integer(n) => n as usize,
+            _ => 0,
+        };
+        let mut copied = 0;
+        let mut cursor:Vec<u8> = b"0".to_vec();
+        loop {
+            let reply = try!(self.query(&[b"SCAN", &cursor, b"COUNT", b"1000"]));
+            let mut page = match reply {
+                Reply::Array(a) => a,
+                _ => return Err("unexpected SCAN reply".to_owned()),
+            };
+            if page.len() != 2 {
+                return Err("unexpected SCAN reply".to_owned());
+            }
+            let keys = match page.pop().unwrap() {
+                Reply::Array(a) => a,
+                _ => return Err("unexpected SCAN reply".to_owned()),
+            };
+            cursor = match page.pop().unwrap() {
+                Reply::Data(d) => d,
+                _ => return Err("unexpected SCAN reply".to_owned()),
+            };
+            for key in keys {
+                let key = match key {
+                    Reply::Data(d) => d,
+                    _ => return Err("unexpected SCAN key".to_owned()),
+                };
+                let payload = match try!(self.query(&[b"DUMP", &key])) {
+                    Reply::Data(d) => d,
+                    Reply::Nil => continue,
+                    _ => return Err("unexpected DUMP reply".to_owned()),
+                };
+                let pttl = match try!(self.query(&[b"PTTL", &key])) {
+                    Reply::Integer(n) => if n < 0 { 0 } else { n },
+                    _ => 0,
+                };
+                let pttl = format!("{}", pttl);
+                try!(dst.query(&[b"RESTORE", &key, pttl.as_bytes(), &payload, b"REPLACE"]));
+                copied += 1;
+            }
+            if let Some(cb) = progress {
+                let remaining = if total > copied { total - copied } else { 0 };
+                cb(Progress { remaining: remaining, total: total });
+            }
+            if cursor == b"0" {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Progress of an ongoing [`backup`](struct.Rlite.html#method.backup).
+pub struct Progress {
+    /// Estimated number of keys still to be copied.
+    pub remaining: usize,
+    /// Estimated total number of keys in the source database.
+    pub total: usize,
+}
+
+/// Decodes a [`Reply`] into a concrete Rust type.
+///
+/// This is the counterpart of [`ToArg`] and lets callers of
+/// [`Rlite::command`](struct.Rlite.html#method.command) pull a typed value out
+/// of a reply instead of pattern matching on every call site.
+pub trait FromReply: Sized {
+    /// Converts `reply` into `Self`, returning a descriptive error on a type
+    /// mismatch.
+    fn from_reply(reply: Reply) -> Result<Self, String>;
+}
+
+impl FromReply for i64 {
+    fn from_reply(reply: Reply) -> Result<Self, String> {
+        match reply {
+            Reply::Integer(n) => Ok(n),
+            other => Err(format!("cannot decode {:?} as i64", other)),
+        }
+    }
+}
+
+impl FromReply for String {
+    fn from_reply(reply: Reply) -> Result<Self, String> {
+        match reply {
+            Reply::Status(s) => Ok(s),
+            Reply::Data(d) => String::from_utf8(d).map_err(|e| format!("{}", e)),
+            other => Err(format!("cannot decode {:?} as String", other)),
+        }
+    }
+}
+
+impl FromReply for Vec<u8> {
+    fn from_reply(reply: Reply) -> Result<Self, String> {
+        match reply {
+            Reply::Data(d) => Ok(d),
+            Reply::Status(s) => Ok(s.into_bytes()),
+            other => Err(format!("cannot decode {:?} as Vec<u8>", other)),
+        }
+    }
+}
+
+impl FromReply for bool {
+    fn from_reply(reply: Reply) -> Result<Self, String> {
+        match reply {
+            Reply::Integer(n) => Ok(n != 0),
+            other => Err(format!("cannot decode {:?} as bool", other)),
+        }
+    }
+}
+
+impl FromReply for f64 {
+    fn from_reply(reply: Reply) -> Result<Self, String> {
+        match reply {
+            Reply::Integer(n) => Ok(n as f64),
+            Reply::Data(d) => {
+                let s = try!(String::from_utf8(d).map_err(|e| format!("{}", e)));
+                s.parse().map_err(|e| format!("{}", e))
+            },
+            other => Err(format!("cannot decode {:?} as f64", other)),
+        }
+    }
+}
+
+impl<T: FromReply> FromReply for Option<T> {
+    fn from_reply(reply: Reply) -> Result<Self, String> {
+        match reply {
+            Reply::Nil => Ok(None),
+            other => Ok(Some(try!(T::from_reply(other)))),
+        }
+    }
+}
+
+impl<T: FromReply> FromReply for Vec<T> {
+    fn from_reply(reply: Reply) -> Result<Self, String> {
+        match reply {
+            Reply::Array(a) => {
+                let mut v = Vec::with_capacity(a.len());
+                for element in a {
+                    v.push(try!(T::from_reply(element)));
+                }
+                Ok(v)
+            },
+            other => Err(format!("cannot decode {:?} as Vec", other)),
+        }
+    }
+}
+
+/// Encodes a value into the raw bytes of a single command argument.
+///
+/// Implemented for the scalar types callers most commonly hand to redis
+/// commands, so that [`Rlite::command`](struct.Rlite.html#method.command) can
+/// take a heterogeneous argument list.
+pub trait ToArg {
+    /// Returns the argument as a binary-safe byte buffer.
+    fn to_arg(&self) -> Vec<u8>;
+}
+
+impl<'a> ToArg for &'a str {
+    fn to_arg(&self) -> Vec<u8> { self.as_bytes().to_vec() }
+}
+
+impl ToArg for String {
+    fn to_arg(&self) -> Vec<u8> { self.as_bytes().to_vec() }
+}
+
+impl<'a> ToArg for &'a [u8] {
+    fn to_arg(&self) -> Vec<u8> { self.to_vec() }
+}
+
+macro_rules! to_arg_display {
+    ($($ty: ty),*) => {$(
+        impl ToArg for $ty {
+            fn to_arg(&self) -> Vec<u8> { format!("{}", self).into_bytes() }
+        }
+    )*}
+}
+
+to_arg_display!(i32, i64, u32, u64, usize, isize, f32, f64);
+
+impl Rlite {
+    /// Writes a command built from typed arguments and decodes its reply into
+    /// the requested type.
+    ///
+    /// ```ignore
+    /// let n: i64 = db.command(&[&"LPUSH", &"key", &1, &2]).unwrap();
+    /// ```
+    pub fn command<T: FromReply>(&self, args: &[&dyn ToArg]) -> Result<T, String> {
+        let owned:Vec<Vec<u8>> = args.iter().map(|a| a.to_arg()).collect();
+        let refs:Vec<&[u8]> = owned.iter().map(|v| v.as_slice()).collect();
+        let reply = try!(self.query(&refs));
+        T::from_reply(reply)
+    }
+
+    /// Starts a transaction by sending `MULTI`.
+    ///
+    /// The returned [`Transaction`] borrows the connection, so no
+    /// non-transactional command can be interleaved while the guard is live.
+    /// If the guard is dropped without [`commit`](struct.Transaction.html#method.commit)
+    /// the transaction is aborted with `DISCARD`.
+    pub fn transaction(&mut self) -> Result<Transaction, String> {
+        match try!(self.query(&[b"MULTI"])) {
+            Reply::Status(_) => Ok(Transaction { conn: self, done: false }),
+            other => Err(format!("unexpected MULTI reply: {:?}", other)),
+        }
+    }
+
+    /// Opens a string value for incremental I/O.
+    ///
+    /// The returned [`Blob`] implements [`Read`], [`Write`] and [`Seek`] so
+    /// large values can be streamed with a fixed-size buffer instead of being
+    /// materialized in a single `Reply::Data`. `Read` is backed by `GETRANGE`,
+    /// `Write` by `SETRANGE`, and `Seek` by `STRLEN`.
+    pub fn open_blob(&self, key: &[u8]) -> Result<Blob, String> {
+        Ok(Blob { conn: self, key: key.to_vec(), offset: 0 })
+    }
+
+    /// Starts a [`Pipeline`] that buffers commands locally and submits them in
+    /// a single batch.
+    ///
+    /// The engine already queues each command's reply, so a pipeline appends
+    /// every buffered command and then drains exactly that many replies in
+    /// order, so for bulk loads the caller does not have to call `read_reply`
+    /// after each command.
+    pub fn pipeline(&self) -> Pipeline {
+        Pipeline { conn: self, commands: Vec::new() }
+    }
+}
+
+/// A batch of commands submitted together and drained in one call.
+pub struct Pipeline<'a> {
+    conn: &'a Rlite,
+    commands: Vec<Vec<Vec<u8>>>,
+}
+
+impl<'a> Pipeline<'a> {
+    /// Buffers `command` to be sent when [`execute`](#method.execute) is called.
+    pub fn add(&mut self, command: &[&[u8]]) -> &mut Self {
+        self.commands.push(command.iter().map(|c| c.to_vec()).collect());
+        self
+    }
+
+    /// Appends every buffered command and drains exactly that many replies,
+    /// returning one result per command in order.
+    ///
+    /// An error reply is carried in place as the `Err` entry at its own
+    /// position, so a failing command never hides the results of the ones
+    /// around it. The outer `Err` is reserved for a failure to submit the
+    /// batch at all.
+    pub fn execute(self) -> Result<Vec<Result<Reply, String>>, String> {
+        for command in &self.commands {
+            let args:Vec<&[u8]> = command.iter().map(|c| c.as_slice()).collect();
+            if self.conn.write_command(&args).is_err() {
+                return Err("failed to write command".to_owned());
+            }
+        }
+        let mut replies = Vec::with_capacity(self.commands.len());
+        for _ in 0..self.commands.len() {
+            replies.push(self.conn.read_reply());
+        }
+        Ok(replies)
+    }
+}
+
+/// An incremental handle to the string value stored at a key.
+///
+/// The key is kept as a `Vec<u8>` so binary-safe keys keep working.
+pub struct Blob<'a> {
+    conn: &'a Rlite,
+    key: Vec<u8>,
+    offset: u64,
+}
+
+fn blob_error(e: String) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+impl<'a> Read for Blob<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let start = format!("{}", self.offset);
+        let end = format!("{}", self.offset + buf.len() as u64 - 1);
+        let reply = try!(self.conn.query(&[b"GETRANGE", &self.key, start.as_bytes(), end.as_bytes()])
+            .map_err(blob_error));
+        let data = match reply {
+            Reply::Data(d) => d,
+            Reply::Nil => return Ok(0),
+            other => return Err(blob_error(format!("unexpected GETRANGE reply: {:?}", other))),
+        };
+        let n = data.len();
+        buf[..n].copy_from_slice(&data);
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+impl<'a> Write for Blob<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let offset = format!("{}", self.offset);
+        let reply = try!(self.conn.query(&[b"SETRANGE", &self.key, offset.as_bytes(), buf])
+            .map_err(blob_error));
+        match reply {
+            Reply::Integer(_) => {},
+            other => return Err(blob_error(format!("unexpected SETRANGE reply: {:?}", other))),
+        }
+        self.offset += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> Seek for Blob<'a> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let base = match pos {
+            SeekFrom::Start(n) => n as i64,
+            SeekFrom::Current(d) => self.offset as i64 + d,
+            SeekFrom::End(d) => {
+                let len = match try!(self.conn.query(&[b"STRLEN", &self.key]).map_err(blob_error)) {
+                    Reply::Integer(n) => n,
+                    other => return Err(blob_error(format!("unexpected STRLEN reply: {:?}", other))),
+                };
+                len + d
+            },
+        };
+        if base < 0 {
+            return Err(blob_error("cannot seek before start of blob".to_owned()));
+        }
+        self.offset = base as u64;
+        Ok(self.offset)
+    }
+}
+
+/// An in-flight `MULTI`/`EXEC` transaction.
+///
+/// Commands are buffered on the server with [`queue`](#method.queue) and
+/// applied atomically by [`commit`](#method.commit). Dropping the guard without
+/// committing sends `DISCARD`.
+pub struct Transaction<'a> {
+    conn: &'a mut Rlite,
+    done: bool,
+}
+
+impl<'a> Transaction<'a> {
+    /// Queues `command` inside the transaction, consuming its `QUEUED` status.
+    pub fn queue(&self, command: &[&[u8]]) -> Result<(), String> {
+        match try!(self.conn.query(command)) {
+            Reply::Status(_) => Ok(()),
+            other => Err(format!("unexpected queue reply: {:?}", other)),
+        }
+    }
+
+    /// Commits the transaction with `EXEC`, returning the replies of the queued
+    /// commands in order.
+    pub fn commit(mut self) -> Result<Vec<Reply>, String> {
+        self.done = true;
+        match try!(self.conn.query(&[b"EXEC"])) {
+            Reply::Array(a) => Ok(a),
+            Reply::Nil => Ok(Vec::new()),
+            other => Err(format!("unexpected EXEC reply: {:?}", other)),
+        }
+    }
+}
+
+impl<'a> Drop for Transaction<'a> {
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = self.conn.query(&[b"DISCARD"]);
+        }
+    }
 }
 
 impl Drop for Rlite {
@@ -1,7 +1,10 @@
 extern crate rlite;
 
+use std::cell::Cell;
 use std::fs::remove_file;
+use std::io::{Read, Seek, SeekFrom};
 use std::path::Path;
+use std::rc::Rc;
 
 use rlite::{Rlite, Reply};
 
@@ -99,3 +102,118 @@ fn reply_array() {
                 Reply::Data(b"3".to_vec()),
                 ]));
 }
+
+#[test]
+fn backup_roundtrip() {
+    let src = Rlite::memory();
+    src.write_command(&["set".as_bytes(), "key".as_bytes(), "value".as_bytes()]).unwrap();
+    src.read_reply().unwrap();
+    src.write_command(&["pexpire".as_bytes(), "key".as_bytes(), "100000".as_bytes()]).unwrap();
+    src.read_reply().unwrap();
+
+    let mut dst = Rlite::memory();
+    src.backup(&mut dst, None).unwrap();
+
+    dst.write_command(&["get".as_bytes(), "key".as_bytes()]).unwrap();
+    assert_eq!(dst.read_reply().unwrap(), Reply::Data(b"value".to_vec()));
+    dst.write_command(&["pttl".as_bytes(), "key".as_bytes()]).unwrap();
+    match dst.read_reply().unwrap() {
+        Reply::Integer(n) => assert!(n > 0),
+        other => panic!("unexpected pttl reply: {:?}", other),
+    }
+}
+
+#[test]
+fn command_typed_decode() {
+    let conn = Rlite::memory();
+
+    let n: i64 = conn.command(&[&"LPUSH", &"key", &1, &2]).unwrap();
+    assert_eq!(n, 2);
+
+    let items: Vec<Vec<u8>> = conn.command(&[&"LRANGE", &"key", &0, &-1]).unwrap();
+    assert_eq!(items, vec![b"2".to_vec(), b"1".to_vec()]);
+
+    let missing: Option<Vec<u8>> = conn.command(&[&"GET", &"missing"]).unwrap();
+    assert_eq!(missing, None);
+}
+
+#[test]
+fn command_type_mismatch() {
+    let conn = Rlite::memory();
+    conn.write_command(&["set".as_bytes(), "str".as_bytes(), "value".as_bytes()]).unwrap();
+    conn.read_reply().unwrap();
+
+    let err = conn.command::<i64>(&[&"GET", &"str"]).unwrap_err();
+    assert!(err.contains("cannot decode"));
+}
+
+#[test]
+fn transaction_commit() {
+    let mut conn = Rlite::memory();
+    {
+        let tx = conn.transaction().unwrap();
+        tx.queue(&["set".as_bytes(), "k".as_bytes(), "v".as_bytes()]).unwrap();
+        tx.queue(&["incr".as_bytes(), "n".as_bytes()]).unwrap();
+        assert_eq!(tx.commit().unwrap(), vec![
+                    Reply::Status("OK".to_owned()),
+                    Reply::Integer(1),
+                    ]);
+    }
+    conn.write_command(&["get".as_bytes(), "k".as_bytes()]).unwrap();
+    assert_eq!(conn.read_reply().unwrap(), Reply::Data(b"v".to_vec()));
+}
+
+#[test]
+fn transaction_discard_on_drop() {
+    let mut conn = Rlite::memory();
+    {
+        let tx = conn.transaction().unwrap();
+        tx.queue(&["set".as_bytes(), "k".as_bytes(), "v".as_bytes()]).unwrap();
+    }
+    conn.write_command(&["get".as_bytes(), "k".as_bytes()]).unwrap();
+    assert_eq!(conn.read_reply().unwrap(), Reply::Nil);
+}
+
+#[test]
+fn blob_read_and_seek() {
+    let conn = Rlite::memory();
+    conn.write_command(&["set".as_bytes(), "b".as_bytes(), "hello".as_bytes()]).unwrap();
+    conn.read_reply().unwrap();
+
+    let mut blob = conn.open_blob(b"b").unwrap();
+    let mut buf = [0u8; 3];
+    let n = blob.read(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"hel");
+
+    assert_eq!(blob.seek(SeekFrom::End(0)).unwrap(), 5);
+    assert_eq!(blob.read(&mut buf).unwrap(), 0);
+}
+
+#[test]
+fn trace_fires_on_error() {
+    let mut conn = Rlite::memory();
+    let calls = Rc::new(Cell::new(0));
+    let counter = calls.clone();
+    conn.trace(Some(Box::new(move |_args, _elapsed| {
+        counter.set(counter.get() + 1);
+    })));
+
+    conn.write_command(&["ping".as_bytes(), "1".as_bytes(), "2".as_bytes()]).unwrap();
+    assert!(conn.read_reply().is_err());
+    assert_eq!(calls.get(), 1);
+}
+
+#[test]
+fn pipeline_batch_with_error() {
+    let conn = Rlite::memory();
+    let mut pipe = conn.pipeline();
+    pipe.add(&["set".as_bytes(), "a".as_bytes(), "1".as_bytes()]);
+    pipe.add(&["ping".as_bytes(), "1".as_bytes(), "2".as_bytes()]);
+    pipe.add(&["get".as_bytes(), "a".as_bytes()]);
+
+    let results = pipe.execute().unwrap();
+    assert_eq!(results.len(), 3);
+    assert_eq!(results[0], Ok(Reply::Status("OK".to_owned())));
+    assert!(results[1].is_err());
+    assert_eq!(results[2], Ok(Reply::Data(b"1".to_vec())));
+}